@@ -13,38 +13,114 @@ use crate::mm::PAGE_SIZE;
 use crate::utils::MemoryRegion;
 use alloc::vec::Vec;
 
+use core::cell::Cell;
 use core::mem::size_of;
 use igvm_defs::{MemoryMapEntryType, IGVM_VHS_MEMORY_MAP_ENTRY};
 use igvm_params::{IgvmParamBlock, IgvmParamPage};
 
-const IGVM_MEMORY_ENTRIES_PER_PAGE: usize = PAGE_SIZE / size_of::<IGVM_VHS_MEMORY_MAP_ENTRY>();
-
+// A view over the guest memory map supplied by the IGVM loader.  The map is
+// a contiguous array of IGVM_VHS_MEMORY_MAP_ENTRY whose length is derived
+// from IgvmParamBlock::memory_map_size, so it may span an arbitrary number
+// of pages rather than being limited to a single one.
 #[derive(Clone, Debug)]
-#[repr(C, align(64))]
-pub struct IgvmMemoryMap {
-    memory_map: [IGVM_VHS_MEMORY_MAP_ENTRY; IGVM_MEMORY_ENTRIES_PER_PAGE],
+pub struct IgvmMemoryMap<'a> {
+    memory_map: &'a [IGVM_VHS_MEMORY_MAP_ENTRY],
+}
+
+impl<'a> IgvmMemoryMap<'a> {
+    // Safety: addr must point to at least entry_count valid, initialized
+    // IGVM_VHS_MEMORY_MAP_ENTRY structures that remain alive for 'a.
+    unsafe fn from_addr(addr: VirtAddr, entry_count: usize) -> Self {
+        let memory_map =
+            unsafe { core::slice::from_raw_parts(addr.as_ptr::<IGVM_VHS_MEMORY_MAP_ENTRY>(), entry_count) };
+        Self { memory_map }
+    }
+}
+
+// Classifies a guest physical address against the memory map without
+// linear-scanning it on every query: entries are sorted by starting page
+// number for a binary search, with a single-slot cache for repeat hits.
+#[derive(Debug)]
+pub struct IgvmMemoryRegionIndex {
+    entries: Vec<(usize, usize, MemoryMapEntryType)>,
+    last_hit: Cell<Option<(MemoryRegion<PhysAddr>, MemoryMapEntryType)>>,
+}
+
+impl IgvmMemoryRegionIndex {
+    fn new(entries: Vec<(usize, usize, MemoryMapEntryType)>) -> Self {
+        Self {
+            entries,
+            last_hit: Cell::new(None),
+        }
+    }
+
+    pub fn find_region_containing(
+        &self,
+        gpa: PhysAddr,
+    ) -> Option<(MemoryRegion<PhysAddr>, MemoryMapEntryType)> {
+        if let Some((region, entry_type)) = self.last_hit.get() {
+            if region.contains(gpa) {
+                return Some((region, entry_type));
+            }
+        }
+
+        let page_number = gpa.bits() / PAGE_SIZE;
+        let index = match self
+            .entries
+            .binary_search_by(|&(start_page, ..)| start_page.cmp(&page_number))
+        {
+            Ok(index) => index,
+            Err(0) => return None,
+            Err(index) => index - 1,
+        };
+
+        let (start_page, page_count, entry_type) = self.entries[index];
+        if page_number >= start_page + page_count {
+            return None;
+        }
+
+        let region = MemoryRegion::new(PhysAddr::new(start_page * PAGE_SIZE), page_count * PAGE_SIZE);
+        self.last_hit.set(Some((region, entry_type)));
+        Some((region, entry_type))
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct IgvmParams<'a> {
     igvm_param_block: &'a IgvmParamBlock,
     igvm_param_page: &'a IgvmParamPage,
-    igvm_memory_map: &'a IgvmMemoryMap,
+    igvm_memory_map: IgvmMemoryMap<'a>,
 }
 
 impl IgvmParams<'_> {
-    pub fn new(addr: VirtAddr) -> Self {
+    pub fn new(addr: VirtAddr) -> Result<Self, SvsmError> {
         let param_block = unsafe { &*addr.as_ptr::<IgvmParamBlock>() };
         let param_page_address = addr + param_block.param_page_offset.try_into().unwrap();
         let param_page = unsafe { &*param_page_address.as_ptr::<IgvmParamPage>() };
-        let memory_map_address = addr + param_block.memory_map_offset.try_into().unwrap();
-        let memory_map = unsafe { &*memory_map_address.as_ptr::<IgvmMemoryMap>() };
 
-        Self {
+        // The memory map offset/size are supplied by the (untrusted) IGVM
+        // loader, so bounds-check them against the mapped parameter area
+        // before the slice is constructed rather than trusting them blindly.
+        let param_area_size: usize = param_block.param_area_size.try_into().unwrap();
+        let memory_map_offset: usize = param_block.memory_map_offset.try_into().unwrap();
+        let memory_map_size: usize = param_block.memory_map_size.try_into().unwrap();
+        let memory_map_end = memory_map_offset
+            .checked_add(memory_map_size)
+            .ok_or(Firmware)?;
+        if memory_map_end > param_area_size {
+            return Err(Firmware);
+        }
+
+        let memory_map_address = addr + memory_map_offset;
+        let memory_map_entries = memory_map_size / size_of::<IGVM_VHS_MEMORY_MAP_ENTRY>();
+        let memory_map =
+            unsafe { IgvmMemoryMap::from_addr(memory_map_address, memory_map_entries) };
+
+        Ok(Self {
             igvm_param_block: param_block,
             igvm_param_page: param_page,
             igvm_memory_map: memory_map,
-        }
+        })
     }
 
     pub fn size(&self) -> usize {
@@ -72,42 +148,344 @@ impl IgvmParams<'_> {
         self.igvm_param_block.secrets_page as u64
     }
 
+    // Validates the supplied memory map and returns the prefix of entries
+    // that are actually described.  The map may span an arbitrary number
+    // of pages, so the whole slice is walked rather than stopping at a
+    // single page boundary.
+    fn validated_entries(&self) -> Result<&[IGVM_VHS_MEMORY_MAP_ENTRY], SvsmError> {
+        validate_memory_map_entries(self.igvm_memory_map.memory_map)
+    }
+
+    // Returns one region per memory map entry described by the loader,
+    // together with its entry type, so callers can distinguish MEMORY
+    // from PERSISTENT, PLATFORM_RESERVED, and other VTL2-protectable
+    // ranges instead of having them silently dropped.
+    pub fn get_typed_memory_regions(
+        &self,
+    ) -> Result<Vec<(MemoryRegion<PhysAddr>, MemoryMapEntryType)>, SvsmError> {
+        Ok(typed_regions_from_entries(self.validated_entries()?))
+    }
+
+    // Reuses the same monotonic/non-overlapping validation as
+    // get_memory_regions, so an index can never be built from a
+    // malformed map.
+    pub fn build_memory_region_index(&self) -> Result<IgvmMemoryRegionIndex, SvsmError> {
+        let entries = self
+            .validated_entries()?
+            .iter()
+            .map(|entry| {
+                let start_page: usize = entry.starting_gpa_page_number.try_into().unwrap();
+                let page_count: usize = entry.number_of_pages.try_into().unwrap();
+                (start_page, page_count, entry.entry_type)
+            })
+            .collect();
+
+        Ok(IgvmMemoryRegionIndex::new(entries))
+    }
+
+    // Convenience wrapper around get_typed_memory_regions that keeps only
+    // the regions describing ordinary usable RAM.
     pub fn get_memory_regions(&self) -> Result<Vec<MemoryRegion<PhysAddr>>, SvsmError> {
-        // Count the number of memory entries present.  They must be
-        // non-overlapping and strictly increasing.
-        let mut number_of_entries = 0;
-        let mut next_page_number = 0;
-        for i in 0..IGVM_MEMORY_ENTRIES_PER_PAGE {
-            let entry = &self.igvm_memory_map.memory_map[i];
-            if entry.number_of_pages == 0 {
-                break;
-            }
-            if entry.starting_gpa_page_number < next_page_number {
-                return Err(Firmware);
-            }
-            let next_supplied_page_number = entry.starting_gpa_page_number + entry.number_of_pages;
-            if next_supplied_page_number < next_page_number {
-                return Err(Firmware);
+        Ok(self
+            .get_typed_memory_regions()?
+            .into_iter()
+            .filter(|(_, entry_type)| *entry_type == MemoryMapEntryType::MEMORY)
+            .map(|(region, _)| region)
+            .collect())
+    }
+
+    // Ranges that must not be handed to the allocator as free RAM even
+    // though they fall inside a MEMORY region: the parameter area, the
+    // kernel region, the CPUID page, and the secrets page.
+    fn get_reserved_regions(&self) -> Result<Vec<MemoryRegion<PhysAddr>>, SvsmError> {
+        let kernel_base = PhysAddr::from(self.igvm_param_block.kernel_base);
+        Ok(alloc::vec![
+            MemoryRegion::new(kernel_base, self.size()),
+            self.find_kernel_region()?,
+            MemoryRegion::new(PhysAddr::from(self.get_cpuid_page_address()), PAGE_SIZE),
+            MemoryRegion::new(PhysAddr::from(self.get_secrets_page_address()), PAGE_SIZE),
+        ])
+    }
+
+    // Returns the MEMORY regions described by the loader with the reserved
+    // ranges (parameter area, kernel, CPUID page, secrets page) carved out
+    // via interval subtraction.
+    pub fn get_usable_memory_regions(&self) -> Result<Vec<MemoryRegion<PhysAddr>>, SvsmError> {
+        let reserved = self.get_reserved_regions()?;
+        let regions = self.get_memory_regions()?;
+        Ok(subtract_reserved_ranges(&regions, &reserved))
+    }
+
+    // Zeroes every usable MEMORY page before it is handed to any consumer.
+    // map()/unmap() are called per page so the caller never needs to
+    // provide one giant contiguous mapping.
+    pub fn zero_usable_memory<M, U>(&self, map: M, unmap: U) -> Result<(), SvsmError>
+    where
+        M: FnMut(PhysAddr) -> Result<VirtAddr, SvsmError>,
+        U: FnMut(VirtAddr),
+    {
+        zero_regions(&self.get_usable_memory_regions()?, map, unmap)
+    }
+}
+
+// Maps, zeroes, and unmaps each page of every region in turn so the
+// caller never needs to provide one giant contiguous mapping.
+fn zero_regions<M, U>(regions: &[MemoryRegion<PhysAddr>], mut map: M, mut unmap: U) -> Result<(), SvsmError>
+where
+    M: FnMut(PhysAddr) -> Result<VirtAddr, SvsmError>,
+    U: FnMut(VirtAddr),
+{
+    for region in regions {
+        let start = region.start().bits();
+        let end = region.end().bits();
+        let mut page = start;
+        while page < end {
+            let vaddr = map(PhysAddr::new(page))?;
+            unsafe {
+                core::ptr::write_bytes(vaddr.as_mut_ptr::<u8>(), 0, PAGE_SIZE);
             }
-            next_page_number = next_supplied_page_number;
-            number_of_entries += 1;
+            unmap(vaddr);
+            page += PAGE_SIZE;
         }
+    }
+
+    Ok(())
+}
 
-        // Now loop over the supplied entires and add a region for each
-        // known type.
-        let mut regions: Vec<MemoryRegion<PhysAddr>> = Vec::new();
-        for i in 0..number_of_entries {
-            let entry = &self.igvm_memory_map.memory_map[i];
-            if entry.entry_type == MemoryMapEntryType::MEMORY {
-                let starting_page: usize = entry.starting_gpa_page_number.try_into().unwrap();
-                let number_of_pages: usize = entry.number_of_pages.try_into().unwrap();
-                regions.push(MemoryRegion::new(
+// Converts each validated entry into a region paired with its entry type,
+// preserving types other than MEMORY instead of dropping them.
+fn typed_regions_from_entries(
+    entries: &[IGVM_VHS_MEMORY_MAP_ENTRY],
+) -> Vec<(MemoryRegion<PhysAddr>, MemoryMapEntryType)> {
+    entries
+        .iter()
+        .map(|entry| {
+            let starting_page: usize = entry.starting_gpa_page_number.try_into().unwrap();
+            let number_of_pages: usize = entry.number_of_pages.try_into().unwrap();
+            (
+                MemoryRegion::new(
                     PhysAddr::new(starting_page * PAGE_SIZE),
                     number_of_pages * PAGE_SIZE,
-                ));
+                ),
+                entry.entry_type,
+            )
+        })
+        .collect()
+}
+
+// Validates that entries are non-overlapping and strictly increasing,
+// returning the zero-terminated prefix that's actually described.
+fn validate_memory_map_entries(
+    entries: &[IGVM_VHS_MEMORY_MAP_ENTRY],
+) -> Result<&[IGVM_VHS_MEMORY_MAP_ENTRY], SvsmError> {
+    let mut number_of_entries = 0;
+    let mut next_page_number = 0;
+    for entry in entries {
+        if entry.number_of_pages == 0 {
+            break;
+        }
+        if entry.starting_gpa_page_number < next_page_number {
+            return Err(Firmware);
+        }
+        let next_supplied_page_number = entry.starting_gpa_page_number + entry.number_of_pages;
+        if next_supplied_page_number < next_page_number {
+            return Err(Firmware);
+        }
+        next_page_number = next_supplied_page_number;
+        number_of_entries += 1;
+    }
+
+    Ok(&entries[..number_of_entries])
+}
+
+// Subtracts `reserved` from `regions` via interval subtraction, clipping
+// reserved ranges that straddle a region boundary and dropping a region
+// entirely if a reserved range fully covers it.
+fn subtract_reserved_ranges(
+    regions: &[MemoryRegion<PhysAddr>],
+    reserved: &[MemoryRegion<PhysAddr>],
+) -> Vec<MemoryRegion<PhysAddr>> {
+    let mut reserved = reserved.to_vec();
+    reserved.sort_by_key(|region| region.start().bits());
+
+    let mut usable = Vec::new();
+    for region in regions {
+        let mut cursor = region.start();
+        for reserved_region in reserved.iter().filter(|r| r.overlap(region)) {
+            let clipped_start = core::cmp::max(reserved_region.start().bits(), cursor.bits());
+            let clipped_end = core::cmp::min(reserved_region.end().bits(), region.end().bits());
+            if clipped_start > cursor.bits() {
+                usable.push(MemoryRegion::new(cursor, clipped_start - cursor.bits()));
             }
+            cursor = PhysAddr::new(core::cmp::max(cursor.bits(), clipped_end));
+        }
+        if cursor.bits() < region.end().bits() {
+            usable.push(MemoryRegion::new(cursor, region.end().bits() - cursor.bits()));
         }
+    }
+
+    usable
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(start: usize, len: usize) -> MemoryRegion<PhysAddr> {
+        MemoryRegion::new(PhysAddr::new(start), len)
+    }
+
+    fn map_entry(
+        starting_gpa_page_number: u64,
+        number_of_pages: u64,
+        entry_type: MemoryMapEntryType,
+    ) -> IGVM_VHS_MEMORY_MAP_ENTRY {
+        IGVM_VHS_MEMORY_MAP_ENTRY {
+            starting_gpa_page_number,
+            number_of_pages,
+            entry_type,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn validate_memory_map_entries_spans_multiple_pages() {
+        let entries_per_page = PAGE_SIZE / size_of::<IGVM_VHS_MEMORY_MAP_ENTRY>();
+        let mut entries: Vec<IGVM_VHS_MEMORY_MAP_ENTRY> = (0..entries_per_page + 1)
+            .map(|i| map_entry(i as u64, 1, MemoryMapEntryType::MEMORY))
+            .collect();
+        entries.push(map_entry(0, 0, MemoryMapEntryType::MEMORY));
+
+        let validated = validate_memory_map_entries(&entries).unwrap();
+        assert_eq!(validated.len(), entries_per_page + 1);
+    }
+
+    #[test]
+    fn validate_memory_map_entries_rejects_overlap() {
+        let entries = [
+            map_entry(0, 4, MemoryMapEntryType::MEMORY),
+            map_entry(2, 4, MemoryMapEntryType::MEMORY),
+        ];
+        assert!(validate_memory_map_entries(&entries).is_err());
+    }
+
+    #[test]
+    fn typed_regions_from_entries_preserves_non_memory_types() {
+        let entries = [
+            map_entry(0, 4, MemoryMapEntryType::MEMORY),
+            map_entry(4, 2, MemoryMapEntryType::PERSISTENT),
+            map_entry(6, 1, MemoryMapEntryType::PLATFORM_RESERVED),
+        ];
+
+        let typed = typed_regions_from_entries(&entries);
+        assert_eq!(
+            typed,
+            [
+                (region(0, 4 * PAGE_SIZE), MemoryMapEntryType::MEMORY),
+                (region(4 * PAGE_SIZE, 2 * PAGE_SIZE), MemoryMapEntryType::PERSISTENT),
+                (
+                    region(6 * PAGE_SIZE, PAGE_SIZE),
+                    MemoryMapEntryType::PLATFORM_RESERVED
+                ),
+            ]
+        );
+
+        let memory_only: Vec<_> = typed
+            .into_iter()
+            .filter(|(_, entry_type)| *entry_type == MemoryMapEntryType::MEMORY)
+            .map(|(region, _)| region)
+            .collect();
+        assert_eq!(memory_only, [region(0, 4 * PAGE_SIZE)]);
+    }
+
+    #[test]
+    fn subtract_reserved_ranges_no_overlap() {
+        let regions = [region(0, 4 * PAGE_SIZE)];
+        let reserved = [region(8 * PAGE_SIZE, PAGE_SIZE)];
+        let usable = subtract_reserved_ranges(&regions, &reserved);
+        assert_eq!(usable, [region(0, 4 * PAGE_SIZE)]);
+    }
+
+    #[test]
+    fn subtract_reserved_ranges_fully_covers_region() {
+        let regions = [region(PAGE_SIZE, PAGE_SIZE)];
+        let reserved = [region(0, 4 * PAGE_SIZE)];
+        let usable = subtract_reserved_ranges(&regions, &reserved);
+        assert!(usable.is_empty());
+    }
+
+    #[test]
+    fn subtract_reserved_ranges_straddles_boundary() {
+        let regions = [region(PAGE_SIZE, 2 * PAGE_SIZE)];
+        let reserved = [region(0, 2 * PAGE_SIZE)];
+        let usable = subtract_reserved_ranges(&regions, &reserved);
+        assert_eq!(usable, [region(2 * PAGE_SIZE, PAGE_SIZE)]);
+    }
+
+    #[test]
+    fn subtract_reserved_ranges_multiple_overlapping() {
+        let regions = [region(0, 10 * PAGE_SIZE)];
+        let reserved = [
+            region(PAGE_SIZE, 2 * PAGE_SIZE),
+            region(2 * PAGE_SIZE, 3 * PAGE_SIZE),
+            region(7 * PAGE_SIZE, PAGE_SIZE),
+        ];
+        let usable = subtract_reserved_ranges(&regions, &reserved);
+        assert_eq!(
+            usable,
+            [
+                region(0, PAGE_SIZE),
+                region(5 * PAGE_SIZE, 2 * PAGE_SIZE),
+                region(8 * PAGE_SIZE, 2 * PAGE_SIZE),
+            ]
+        );
+    }
+
+    #[test]
+    fn memory_region_index_finds_containing_region() {
+        let index = IgvmMemoryRegionIndex::new(alloc::vec![
+            (0, 4, MemoryMapEntryType::MEMORY),
+            (8, 4, MemoryMapEntryType::PERSISTENT),
+        ]);
+
+        let (hit, entry_type) = index
+            .find_region_containing(PhysAddr::new(2 * PAGE_SIZE))
+            .unwrap();
+        assert_eq!(hit, region(0, 4 * PAGE_SIZE));
+        assert_eq!(entry_type, MemoryMapEntryType::MEMORY);
+
+        // Repeat lookup within the same region so it is served from the
+        // last-hit cache instead of the binary search.
+        let (hit, entry_type) = index
+            .find_region_containing(PhysAddr::new(3 * PAGE_SIZE))
+            .unwrap();
+        assert_eq!(hit, region(0, 4 * PAGE_SIZE));
+        assert_eq!(entry_type, MemoryMapEntryType::MEMORY);
+
+        assert!(index
+            .find_region_containing(PhysAddr::new(6 * PAGE_SIZE))
+            .is_none());
+    }
+
+    #[test]
+    fn zero_regions_maps_zeroes_and_unmaps_a_single_page_region() {
+        let mut backing = [0xffu8; PAGE_SIZE];
+        let mut mapped = Vec::new();
+        let mut unmapped = 0;
+        let regions = [region(0, PAGE_SIZE)];
+
+        zero_regions(
+            &regions,
+            |gpa| {
+                mapped.push(gpa);
+                Ok(VirtAddr::from(backing.as_mut_ptr() as usize))
+            },
+            |_| unmapped += 1,
+        )
+        .unwrap();
 
-        Ok(regions)
+        assert_eq!(mapped, [PhysAddr::new(0)]);
+        assert_eq!(unmapped, 1);
+        assert!(backing.iter().all(|&b| b == 0));
     }
 }